@@ -0,0 +1,66 @@
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref};
+use usvg::Tree;
+
+use crate::render::tree_to_xobject;
+use crate::util::context::Context;
+use crate::{ConversionOptions, PageOptions, Result};
+
+/// Convert several trees into a single multi-page PDF document.
+///
+/// Each `(tree, page_options)` pair becomes one page, sized from that tree's
+/// own [`Tree::size`] and `page_options.dpi`. All pages are converted
+/// through the same [`Context`], so XObject dedup (see
+/// [`crate::render::tree_to_xobject`]) and any shared resources are emitted
+/// once and referenced from every page that uses them.
+///
+/// Each tree's own `page_options.dpi` is passed through to
+/// [`tree_to_xobject`] so that embedded raster images are downsampled
+/// against the DPI of the page they actually land on, not a single DPI
+/// assumed for the whole document; `Context` is shared across pages, but the
+/// DPI used for that page's image footprint is not.
+pub fn to_pdf_multipage(
+    trees: &[(&Tree, PageOptions)],
+    options: ConversionOptions,
+) -> Result<Vec<u8>> {
+    let mut alloc = Ref::new(1);
+    let catalog_ref = alloc.bump();
+    let page_tree_ref = alloc.bump();
+
+    let mut ctx = Context::new(options, &mut alloc);
+
+    let mut pdf = Pdf::new();
+    let mut page_refs = Vec::with_capacity(trees.len());
+
+    for (tree, page_options) in trees {
+        let page_ref = ctx.alloc_ref();
+        let content_ref = ctx.alloc_ref();
+
+        let mut chunk = pdf_writer::Chunk::new();
+        let xobject_ref = tree_to_xobject(tree, &mut chunk, &mut ctx, page_options.dpi)?;
+
+        let size = tree.size();
+        let width_pt = size.width() / page_options.dpi * 72.0;
+        let height_pt = size.height() / page_options.dpi * 72.0;
+
+        let svg_name = Name(b"S1");
+
+        let mut content = Content::new();
+        content.transform([width_pt, 0.0, 0.0, height_pt, 0.0, 0.0]).x_object(svg_name);
+        pdf.stream(content_ref, &content.finish());
+
+        let mut page = pdf.page(page_ref);
+        page.media_box(Rect::new(0.0, 0.0, width_pt, height_pt));
+        page.parent(page_tree_ref);
+        page.contents(content_ref);
+        page.resources().x_objects().pair(svg_name, xobject_ref);
+        page.finish();
+
+        pdf.extend(&chunk);
+        page_refs.push(page_ref);
+    }
+
+    pdf.catalog(catalog_ref).pages(page_tree_ref);
+    pdf.pages(page_tree_ref).kids(page_refs.iter().copied()).count(page_refs.len() as i32);
+
+    Ok(pdf.finish())
+}
@@ -0,0 +1,240 @@
+use pdf_writer::{Chunk, Content, Filter, Finish};
+use usvg::{ImageKind, Transform};
+
+use crate::util::context::Context;
+use crate::util::resources::ResourceContainer;
+use crate::{ConversionError, ImageEncoding, Result};
+
+/// Render an image node into the content stream.
+///
+/// `accumulated_transform` is the transform in effect at the point the image
+/// is drawn, in the tree's own user-unit space. When `ctx.options
+/// .max_image_dpi` is set, it is combined with that transform and
+/// `ctx.current_page_dpi` (the DPI of the page this render pass was started
+/// for, see [`crate::render::tree_to_xobject`]) to determine the image's
+/// on-page footprint in physical pixels, so that sources exceeding it can be
+/// downsampled before being embedded, see [`downsample_to_footprint`].
+/// Leaving `max_image_dpi` as `None` (the default) preserves the previous
+/// behavior of embedding at native resolution.
+pub fn render(
+    is_visible: bool,
+    kind: &ImageKind,
+    accumulated_transform: Option<Transform>,
+    chunk: &mut Chunk,
+    content: &mut Content,
+    ctx: &mut Context,
+    rc: &mut ResourceContainer,
+) -> Result<()> {
+    if !is_visible {
+        return Ok(());
+    }
+
+    let decoded = decode(kind)?;
+
+    let prepared = match (accumulated_transform, ctx.options.max_image_dpi) {
+        (Some(transform), Some(max_dpi)) => {
+            downsample_to_footprint(decoded, transform, ctx.current_page_dpi, max_dpi)
+        }
+        _ => decoded,
+    };
+
+    let (stream, filter, color_space) = encode(&prepared, ctx)?;
+
+    let x_ref = ctx.alloc_ref();
+
+    let s_mask_ref = if let Some(alpha) = &prepared.alpha {
+        let s_mask_ref = ctx.alloc_ref();
+        let (alpha_stream, alpha_filter) = if ctx.options.compress {
+            (deflate(alpha), Some(Filter::FlateDecode))
+        } else {
+            (alpha.clone(), None)
+        };
+        let mut s_mask = chunk.image_xobject(s_mask_ref, &alpha_stream);
+        s_mask.width(prepared.width as i32);
+        s_mask.height(prepared.height as i32);
+        s_mask.color_space().device_gray();
+        s_mask.bits_per_component(8);
+        if let Some(alpha_filter) = alpha_filter {
+            s_mask.filter(alpha_filter);
+        }
+        s_mask.finish();
+        Some(s_mask_ref)
+    } else {
+        None
+    };
+
+    let mut x_object = chunk.image_xobject(x_ref, &stream);
+    x_object.width(prepared.width as i32);
+    x_object.height(prepared.height as i32);
+    x_object.color_space().write(color_space);
+    x_object.bits_per_component(8);
+    if let Some(filter) = filter {
+        x_object.filter(filter);
+    }
+
+    if let Some(s_mask_ref) = s_mask_ref {
+        x_object.s_mask(s_mask_ref);
+    }
+
+    x_object.finish();
+
+    let name = rc.add_x_object(x_ref);
+    content.save_state_checked()?;
+    content.x_object(name);
+    content.restore_state();
+
+    Ok(())
+}
+
+/// A decoded, straight-alpha RGB(A) pixel buffer.
+struct DecodedImage {
+    width: u32,
+    height: u32,
+    /// Tightly packed RGB pixels, row-major, top-to-bottom.
+    rgb: Vec<u8>,
+    /// Tightly packed 8-bit alpha, one byte per pixel, if the source has one.
+    alpha: Option<Vec<u8>>,
+}
+
+fn decode(kind: &ImageKind) -> Result<DecodedImage> {
+    let image = image::load_from_memory(match kind {
+        ImageKind::JPEG(data) | ImageKind::PNG(data) | ImageKind::GIF(data) => data,
+        _ => return Err(ConversionError::InvalidImage),
+    })
+    .map_err(|_| ConversionError::InvalidImage)?
+    .to_rgba8();
+
+    let (width, height) = image.dimensions();
+    let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+    let mut alpha = Vec::with_capacity((width * height) as usize);
+    let mut has_alpha = false;
+
+    for pixel in image.pixels() {
+        rgb.extend_from_slice(&pixel.0[..3]);
+        alpha.push(pixel.0[3]);
+        has_alpha |= pixel.0[3] != 255;
+    }
+
+    Ok(DecodedImage { width, height, rgb, alpha: has_alpha.then_some(alpha) })
+}
+
+/// Downsample `image` to the pixel footprint it actually occupies on the
+/// page, computed from `accumulated_transform`, the page's own `page_dpi`
+/// (`PageOptions::dpi`, which is what relates the tree's user units to
+/// physical points — see `multipage.rs`'s identical `width_pt` conversion),
+/// and `max_dpi` (`ConversionOptions::max_image_dpi`, the quality cap).
+///
+/// Uses a separable box filter (an average over each source-pixel window)
+/// since it is cheap and artifact-free for the shrink-only case this is
+/// restricted to; upsampling is left untouched.
+fn downsample_to_footprint(
+    image: DecodedImage,
+    accumulated_transform: Transform,
+    page_dpi: f32,
+    max_dpi: f32,
+) -> DecodedImage {
+    let on_page_width = accumulated_transform.sx.abs().max(f32::EPSILON);
+    let on_page_height = accumulated_transform.sy.abs().max(f32::EPSILON);
+
+    // User units -> points (same conversion `multipage.rs` uses for the
+    // page's own media box), then points -> target pixels at `max_dpi`.
+    let on_page_width_pt = on_page_width / page_dpi * 72.0;
+    let on_page_height_pt = on_page_height / page_dpi * 72.0;
+
+    let target_w = (on_page_width_pt / 72.0 * max_dpi).ceil().max(1.0) as u32;
+    let target_h = (on_page_height_pt / 72.0 * max_dpi).ceil().max(1.0) as u32;
+
+    if target_w >= image.width && target_h >= image.height {
+        return image;
+    }
+
+    let target_w = target_w.min(image.width).max(1);
+    let target_h = target_h.min(image.height).max(1);
+
+    let rgb = box_downsample(&image.rgb, image.width, image.height, 3, target_w, target_h);
+    let alpha = image
+        .alpha
+        .as_ref()
+        .map(|a| box_downsample(a, image.width, image.height, 1, target_w, target_h));
+
+    DecodedImage { width: target_w, height: target_h, rgb, alpha }
+}
+
+/// Box-downsample a `channels`-interleaved pixel buffer from `src_w`×`src_h`
+/// to `dst_w`×`dst_h` by averaging each destination pixel's source window.
+fn box_downsample(
+    src: &[u8],
+    src_w: u32,
+    src_h: u32,
+    channels: u32,
+    dst_w: u32,
+    dst_h: u32,
+) -> Vec<u8> {
+    let mut dst = vec![0u8; (dst_w * dst_h * channels) as usize];
+
+    for dy in 0..dst_h {
+        let y0 = dy * src_h / dst_h;
+        let y1 = ((dy + 1) * src_h / dst_h).max(y0 + 1).min(src_h);
+
+        for dx in 0..dst_w {
+            let x0 = dx * src_w / dst_w;
+            let x1 = ((dx + 1) * src_w / dst_w).max(x0 + 1).min(src_w);
+
+            let mut sums = [0u32; 4];
+            let mut count = 0u32;
+
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let idx = ((y * src_w + x) * channels) as usize;
+                    for c in 0..channels as usize {
+                        sums[c] += src[idx + c] as u32;
+                    }
+                    count += 1;
+                }
+            }
+
+            let dst_idx = ((dy * dst_w + dx) * channels) as usize;
+            for c in 0..channels as usize {
+                dst[dst_idx + c] = (sums[c] / count.max(1)) as u8;
+            }
+        }
+    }
+
+    dst
+}
+
+fn encode(
+    image: &DecodedImage,
+    ctx: &Context,
+) -> Result<(Vec<u8>, Option<Filter>, pdf_writer::types::ColorSpace)> {
+    match ctx.options.image_recompress {
+        Some(ImageEncoding::Jpeg { quality }) => {
+            let stream = encode_jpeg(image, quality)?;
+            Ok((stream, Some(Filter::DctDecode), pdf_writer::types::ColorSpace::DeviceRgb))
+        }
+        None => {
+            let mut stream = image.rgb.clone();
+            let filter = if ctx.options.compress {
+                stream = deflate(&stream);
+                Some(Filter::FlateDecode)
+            } else {
+                None
+            };
+            Ok((stream, filter, pdf_writer::types::ColorSpace::DeviceRgb))
+        }
+    }
+}
+
+fn encode_jpeg(image: &DecodedImage, quality: u8) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut encoder =
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality);
+    encoder
+        .encode(&image.rgb, image.width, image.height, image::ColorType::Rgb8)
+        .map_err(|_| ConversionError::InvalidImage)?;
+    Ok(out)
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec_zlib(data, 6)
+}
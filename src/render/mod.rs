@@ -1,5 +1,16 @@
+//! # XObject dedup scope
+//!
+//! [`tree_to_xobject`] and [`tree_to_xobject_fit`] cache and reuse form
+//! XObjects for structurally-identical whole trees converted through the
+//! same [`Context`] (e.g. the same tree placed on several pages of
+//! [`crate::to_pdf_multipage`]), via [`emit_xobject`]. That is the only
+//! place in this module with a cache. Nodes inside a tree, including
+//! repeated `<symbol>`/`<use>` instances, are not deduplicated, and the
+//! `pattern`, `gradient`, `mask`, and `clip_path` renderers allocate and
+//! emit their own PDF objects every time they are invoked.
+
 use pdf_writer::{Chunk, Content, Filter, Finish, Name, Ref};
-use usvg::{Node, Size, Transform, Tree};
+use usvg::{Align, AspectRatio, Node, NonZeroRect, Rect, Size, Transform, Tree};
 
 use crate::util::context::Context;
 use crate::util::helper::{ContentExt, RectExt, TransformExt};
@@ -42,20 +53,75 @@ pub fn tree_to_stream(
     Ok(())
 }
 
+/// DPI assumed for entry points that have no page of their own to size
+/// against (e.g. [`crate::to_chunk`], which hands back a bare, unplaced
+/// XObject). Matches the CSS/SVG definition of a user unit (1/96 inch), i.e.
+/// "assume no page-relative scaling" rather than picking an arbitrary print
+/// resolution.
+pub(crate) const DEFAULT_PAGE_DPI: f32 = 96.0;
+
 /// Convert a tree into a XObject of size 1x1, similar to an image.
-pub fn tree_to_xobject(tree: &Tree, chunk: &mut Chunk, ctx: &mut Context) -> Result<Ref> {
+///
+/// `page_dpi` is the DPI of the page this XObject will ultimately be placed
+/// on (see [`PageOptions::dpi`](crate::PageOptions::dpi)); it is used to
+/// convert embedded raster images' on-page footprint from the tree's user
+/// units into points, see [`image::render`]. Callers with no page of their
+/// own (like [`crate::to_chunk`]) should pass [`DEFAULT_PAGE_DPI`].
+///
+/// A tree that is structurally identical to one already emitted through the
+/// same `ctx` (e.g. the same tree on several pages) is served from `ctx`'s
+/// XObject cache instead of being re-emitted, see [`emit_xobject`]. See the
+/// module docs above for what this dedup does not cover.
+pub fn tree_to_xobject(
+    tree: &Tree,
+    chunk: &mut Chunk,
+    ctx: &mut Context,
+    page_dpi: f32,
+) -> Result<Ref> {
     let bbox = tree.size().to_non_zero_rect(0.0, 0.0);
+    let matrix = [1.0 / bbox.width(), 0.0, 0.0, 1.0 / bbox.height(), 0.0, 0.0];
+
+    emit_xobject(tree, chunk, ctx, bbox, matrix, page_dpi)
+}
+
+/// Shared implementation behind [`tree_to_xobject`] and
+/// [`tree_to_xobject_fit`]: emit `tree` as a form XObject with the given
+/// `bbox`/`matrix`, or return the cached `Ref` from a previous call with the
+/// same tree, bbox, matrix and page DPI under the current options.
+fn emit_xobject(
+    tree: &Tree,
+    chunk: &mut Chunk,
+    ctx: &mut Context,
+    bbox: NonZeroRect,
+    matrix: [f32; 6],
+    page_dpi: f32,
+) -> Result<Ref> {
+    let (hash, key) = structural_key(tree, ctx, bbox, matrix, page_dpi);
+
+    let bucket = ctx.xobject_cache.entry(hash).or_default();
+    if let Some((_, cached)) = bucket.iter().find(|(k, _)| *k == key) {
+        return Ok(*cached);
+    }
+
     let x_ref = ctx.alloc_ref();
 
     let mut rc = ResourceContainer::new();
 
+    // `Context` is shared across every page of a `to_pdf_multipage` call
+    // (so that the cache above can dedup across pages), but the DPI a
+    // raster image should be downsampled against is per-page. Stash it on
+    // `ctx` for the duration of this subtree so `image::render` (reached
+    // only indirectly, through `tree_to_stream`) can read it back without
+    // threading a new parameter through every `Render` impl.
+    ctx.current_page_dpi = page_dpi;
+
     let mut content = Content::new();
     tree_to_stream(tree, chunk, &mut content, ctx, &mut rc)?;
     let stream = ctx.finish_content(content);
 
     let mut x_object = chunk.form_xobject(x_ref, &stream);
     x_object.bbox(bbox.to_pdf_rect());
-    x_object.matrix([1.0 / bbox.width(), 0.0, 0.0, 1.0 / bbox.height(), 0.0, 0.0]);
+    x_object.matrix(matrix);
 
     if ctx.options.compress {
         x_object.filter(Filter::FlateDecode);
@@ -67,9 +133,142 @@ pub fn tree_to_xobject(tree: &Tree, chunk: &mut Chunk, ctx: &mut Context) -> Res
     resources.finish();
     x_object.finish();
 
+    // Re-borrow: `tree_to_stream` above took `ctx` mutably, invalidating
+    // the earlier `bucket` borrow.
+    ctx.xobject_cache.entry(hash).or_default().push((key, x_ref));
+
     Ok(x_ref)
 }
 
+/// Compute a cache key for everything that affects the bytes [`emit_xobject`]
+/// would write for `tree` under the current options and the given
+/// `bbox`/`matrix` (geometry, transforms, color space, compression), plus a
+/// cheap 64-bit hash of that key for bucketing.
+///
+/// The full key (not just its hash) is compared on a cache hit, so a hash
+/// collision between two genuinely different trees can never make one
+/// silently render as the other; at worst it costs an extra string
+/// comparison.
+fn structural_key(
+    tree: &Tree,
+    ctx: &Context,
+    bbox: NonZeroRect,
+    matrix: [f32; 6],
+    page_dpi: f32,
+) -> (u64, String) {
+    use std::hash::{Hash, Hasher};
+
+    // usvg's `Debug` impl walks the full node tree, which is the cheapest
+    // stable proxy we have for "everything that affects output bytes". If
+    // it ever starts embedding per-instance identifiers, cache hits would
+    // silently drop to zero rather than misrender — see the
+    // `xobject_dedup` tests, which pin both failure directions down.
+    //
+    // `page_dpi` is part of the key (not just an input to `ctx`) because it
+    // changes the pixel footprint `image::render` downsamples embedded
+    // rasters to: two pages at different DPIs must not share one cached
+    // XObject, or whichever page rendered first would silently dictate the
+    // other's image resolution.
+    let key = format!(
+        "{:?}|compress={}|bbox={:?}|matrix={:?}|page_dpi={}",
+        tree, ctx.options.compress, bbox, matrix, page_dpi
+    );
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    (hasher.finish(), key)
+}
+
+/// Convert a tree into a XObject fitted into `target` according to `aspect`,
+/// honoring the SVG's intrinsic size the way a spec-compliant renderer would
+/// (instead of `tree_to_xobject`'s 1x1-normalized form, which leaves all
+/// scaling to the caller's content-stream matrix).
+///
+/// For `meet` (the default, non-`slice` case) the `min` of the x/y scales is
+/// used so the whole tree is visible, letterboxed inside `target`. For
+/// `slice` the `max` scale is used instead, cropping the tree to `target`
+/// via the form's `BBox`. In both cases the remaining slack is distributed
+/// according to `aspect.align` (`xMinYMin`, `xMidYMid`, `xMaxYMax`, etc.).
+///
+/// See [`tree_to_xobject`] for `page_dpi`.
+pub fn tree_to_xobject_fit(
+    tree: &Tree,
+    chunk: &mut Chunk,
+    ctx: &mut Context,
+    target: Rect,
+    aspect: AspectRatio,
+    page_dpi: f32,
+) -> Result<Ref> {
+    let size = tree.size();
+    let (scale_x, scale_y) = fit_scale(size, target, aspect);
+    let (tx, ty) = align_offset(aspect.align, target, size.width() * scale_x, size.height() * scale_y);
+
+    // Express the visible window back in the tree's own coordinate system so
+    // that slicing crops via BBox rather than overflowing `target`.
+    let local_bbox = NonZeroRect::from_xywh(
+        (target.x() - tx) / scale_x,
+        (target.y() - ty) / scale_y,
+        target.width() / scale_x,
+        target.height() / scale_y,
+    )
+    .and_then(|rect| rect.intersect(&size.to_non_zero_rect(0.0, 0.0)))
+    .unwrap_or_else(|| size.to_non_zero_rect(0.0, 0.0));
+
+    emit_xobject(tree, chunk, ctx, local_bbox, [scale_x, 0.0, 0.0, scale_y, tx, ty], page_dpi)
+}
+
+/// Compute the x/y scale factors mapping `size` into `target` per `aspect`.
+///
+/// `Align::None` means "do not preserve aspect ratio": x and y are scaled
+/// independently to exactly fill `target`.
+fn fit_scale(size: Size, target: Rect, aspect: AspectRatio) -> (f32, f32) {
+    let scale_x = target.width() / size.width();
+    let scale_y = target.height() / size.height();
+
+    if aspect.align == Align::None {
+        (scale_x, scale_y)
+    } else if aspect.slice {
+        (scale_x.max(scale_y), scale_x.max(scale_y))
+    } else {
+        (scale_x.min(scale_y), scale_x.min(scale_y))
+    }
+}
+
+/// Position a `scaled_w`×`scaled_h` box inside `target` according to `align`.
+///
+/// This offset is applied in the same local bbox space that
+/// [`tree_to_stream`] hands to the content stream, and `tree_to_stream`
+/// flips Y before emitting anything (its `initial_transform`) to go from
+/// SVG's y-down space to PDF's y-up space. So the numeric minimum of that
+/// local space — `target.y()` — ends up at the *bottom* of the rendered
+/// result, not the top: `YMin` (align to the top per the SVG spec) has to
+/// map to `target`'s numeric maximum edge, and `YMax` to its numeric
+/// minimum, the reverse of how the x-axis branches read.
+fn align_offset(align: Align, target: Rect, scaled_w: f32, scaled_h: f32) -> (f32, f32) {
+    let x = match align {
+        Align::None | Align::XMinYMin | Align::XMinYMid | Align::XMinYMax => target.x(),
+        Align::XMidYMin | Align::XMidYMid | Align::XMidYMax => {
+            target.x() + (target.width() - scaled_w) / 2.0
+        }
+        Align::XMaxYMin | Align::XMaxYMid | Align::XMaxYMax => {
+            target.x() + target.width() - scaled_w
+        }
+    };
+
+    let y = match align {
+        Align::None | Align::XMinYMin | Align::XMidYMin | Align::XMaxYMin => {
+            target.y() + target.height() - scaled_h
+        }
+        Align::XMinYMid | Align::XMidYMid | Align::XMaxYMid => {
+            target.y() + (target.height() - scaled_h) / 2.0
+        }
+        Align::XMinYMax | Align::XMidYMax | Align::XMaxYMax => target.y(),
+    };
+
+    (x, y)
+}
+
 /// Render an externally-provided image into the content stream.
 ///
 /// This emits the same coordinate transforms that the normal image rendering
@@ -115,6 +314,46 @@ fn render_external_image(
     Ok(())
 }
 
+/// Resolve a `Node::Image` whose href usvg left unembedded (an external
+/// file or URL reference), via `ctx.options.resource_resolver`, returning
+/// the raw bytes to feed through the normal image path.
+///
+/// Returns `Ok(None)` both when there is no resolver configured and when
+/// the image is already embedded (nothing to resolve), so callers can fall
+/// through to the existing decode path in either case.
+fn resolve_external_href(
+    image: &usvg::Image,
+    ctx: &Context,
+) -> Result<Option<Vec<u8>>> {
+    let Some(resolver) = ctx.options.resource_resolver.as_ref() else {
+        return Ok(None);
+    };
+
+    let usvg::ImageKind::Unresolved(href) = image.kind() else {
+        return Ok(None);
+    };
+
+    resolver.resolve(href, &ctx.options.base_dir).map(Some).map_err(Into::into)
+}
+
+/// Sniff `bytes` for one of the raster formats the `image` renderer
+/// understands, wrapping it in the matching [`usvg::ImageKind`] without
+/// assuming a fixed format, since a resolved href can point at anything the
+/// allowlist permits reading.
+fn sniff_raster_kind(bytes: &[u8]) -> Option<usvg::ImageKind> {
+    let data = std::sync::Arc::new(bytes.to_vec());
+
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(usvg::ImageKind::PNG(data))
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(usvg::ImageKind::JPEG(data))
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some(usvg::ImageKind::GIF(data))
+    } else {
+        None
+    }
+}
+
 trait Render {
     fn render(
         &self,
@@ -150,12 +389,47 @@ impl Render for Node {
                     return render_external_image(image, ext, content, rc);
                 }
 
+                // An unresolved external `<image href="...">` is only
+                // renderable if the caller configured an allowlisted
+                // resolver; a path-traversal attempt surfaces as
+                // `ConversionError::ResourceAccessDenied` rather than
+                // silently reading outside the allowlist. This is the only
+                // node `resource_resolver` is wired up to — see the open
+                // limitation documented at the top of `util::resolver` for
+                // why external `<use>` isn't covered.
+                #[cfg(feature = "image")]
+                if let Some(bytes) = resolve_external_href(image, ctx)? {
+                    return match sniff_raster_kind(&bytes) {
+                        Some(kind) => image::render(
+                            image.is_visible(),
+                            &kind,
+                            Some(accumulated_transform),
+                            chunk,
+                            content,
+                            ctx,
+                            rc,
+                        ),
+                        // A resolved href that isn't a raster format we can
+                        // decode (e.g. it legitimately points at an SVG, or
+                        // at something unrecognized) must not abort the
+                        // whole document; drop just this image, matching
+                        // the pre-existing behavior for references usvg
+                        // couldn't resolve at all.
+                        None => {
+                            log::warn!(
+                                "Resolved external image was not a supported raster format. Skipping."
+                            );
+                            Ok(())
+                        }
+                    };
+                }
+
                 #[cfg(feature = "image")]
                 {
                     image::render(
                         image.is_visible(),
                         image.kind(),
-                        None,
+                        Some(accumulated_transform),
                         chunk,
                         content,
                         ctx,
@@ -193,3 +467,94 @@ impl Render for Node {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    // `tree_to_xobject_fit` needs a `Context`/`Chunk` to exercise end to
+    // end, so these pin down the pure geometry it relies on instead:
+    // `fit_scale`'s meet/slice choice and `align_offset`'s placement for
+    // all nine `preserveAspectRatio` alignments.
+    use super::{align_offset, fit_scale, Align, AspectRatio};
+    use usvg::{Rect, Size};
+
+    fn wide_target() -> Rect {
+        Rect::from_xywh(0.0, 0.0, 200.0, 100.0).unwrap()
+    }
+
+    fn tall_target() -> Rect {
+        Rect::from_xywh(0.0, 0.0, 100.0, 200.0).unwrap()
+    }
+
+    fn square() -> Size {
+        Size::from_wh(100.0, 100.0).unwrap()
+    }
+
+    #[test]
+    fn meet_uses_min_scale() {
+        let aspect = AspectRatio { defer: false, align: Align::XMidYMid, slice: false };
+        let (sx, sy) = fit_scale(square(), wide_target(), aspect);
+        assert_eq!((sx, sy), (1.0, 1.0));
+    }
+
+    #[test]
+    fn slice_uses_max_scale() {
+        let aspect = AspectRatio { defer: false, align: Align::XMidYMid, slice: true };
+        let (sx, sy) = fit_scale(square(), wide_target(), aspect);
+        assert_eq!((sx, sy), (2.0, 2.0));
+    }
+
+    #[test]
+    fn align_none_scales_independently() {
+        let aspect = AspectRatio { defer: false, align: Align::None, slice: false };
+        let (sx, sy) = fit_scale(square(), wide_target(), aspect);
+        assert_eq!((sx, sy), (2.0, 1.0));
+    }
+
+    #[test]
+    fn align_offset_covers_all_nine_alignments() {
+        let target = wide_target();
+        let (scaled_w, scaled_h) = (100.0, 100.0);
+
+        let cases = [
+            (Align::XMinYMin, (0.0, 0.0)),
+            (Align::XMidYMin, (50.0, 0.0)),
+            (Align::XMaxYMin, (100.0, 0.0)),
+            (Align::XMinYMid, (0.0, 0.0)),
+            (Align::XMidYMid, (50.0, 0.0)),
+            (Align::XMaxYMid, (100.0, 0.0)),
+            (Align::XMinYMax, (0.0, 0.0)),
+            (Align::XMidYMax, (50.0, 0.0)),
+            (Align::XMaxYMax, (100.0, 0.0)),
+        ];
+
+        for (align, expected) in cases {
+            let offset = align_offset(align, target, scaled_w, scaled_h);
+            assert_eq!(offset, expected, "alignment {align:?} placed the content wrong");
+        }
+    }
+
+    /// `wide_target` above has zero vertical slack (`scaled_h == target
+    /// .height()`), so it can't distinguish `YMin` from `YMax` — both land
+    /// at `0.0` either way. Exercise a target with vertical slack instead,
+    /// the same way `wide_target` already exercises horizontal slack, to
+    /// pin down which numeric edge `YMin`/`YMax` actually map to once
+    /// `tree_to_stream`'s coordinate flip is accounted for.
+    #[test]
+    fn align_offset_y_axis_respects_the_coordinate_flip() {
+        let target = tall_target();
+        let (scaled_w, scaled_h) = (100.0, 100.0);
+
+        let cases = [
+            // `YMin` (top-align per the SVG spec) lands at the numeric
+            // *maximum* edge of this flipped local space, not `target.y()`.
+            (Align::XMinYMin, (0.0, 100.0)),
+            (Align::XMinYMid, (0.0, 50.0)),
+            (Align::XMinYMax, (0.0, 0.0)),
+        ];
+
+        for (align, expected) in cases {
+            let offset = align_offset(align, target, scaled_w, scaled_h);
+            assert_eq!(offset, expected, "alignment {align:?} placed the content wrong");
+        }
+    }
+}
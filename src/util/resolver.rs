@@ -0,0 +1,195 @@
+//! # Scope: `resource_resolver` covers external `<image>` only
+//!
+//! [`ResourceResolver`] is called from exactly one place, `render/mod.rs`'s
+//! handling of `Node::Image`, for an unresolved `<image href="...">`.
+//! External `<use href="file.svg#id">` references are not resolved: `usvg`
+//! resolves `<use>` against the current document at parse time and drops
+//! any reference it cannot find there, so no unresolved `<use>` node
+//! reaches this crate's render path.
+
+use std::path::{Path, PathBuf};
+
+use crate::ConversionError;
+
+/// Resolves an external href (from an SVG `<image href="...">`) to bytes,
+/// refusing anything that escapes a configured allowlist of base
+/// directories.
+///
+/// Set via [`crate::ConversionOptions::resource_resolver`]. The resolver is
+/// only consulted for hrefs `usvg` left unresolved (i.e. not already
+/// embedded as `data:` URIs or inlined by the caller). See the module docs
+/// above for the external-`<use>` case this does not cover.
+pub trait ResourceResolver: Send + Sync {
+    /// Resolve `href`, relative to `base_dir` (the converted document's own
+    /// directory), into raw bytes. Must return
+    /// [`ConversionError::ResourceAccessDenied`] for any href that
+    /// canonicalizes outside the allowed base paths.
+    fn resolve(&self, href: &str, base_dir: &Path) -> Result<Vec<u8>, ConversionError>;
+}
+
+/// A [`ResourceResolver`] that reads `file:`/bare-path hrefs only when they
+/// canonicalize to somewhere inside one of `allowed_bases`, and decodes
+/// `data:` URIs unless [`Self::deny_data_uris`] was called.
+///
+/// This is the resolver most callers want: it rejects path traversal
+/// (`../`, symlinks, absolute reroutes) by canonicalizing the requested path
+/// and checking it is still prefixed by one of the allowed bases, rather
+/// than by pattern-matching the href text.
+pub struct AllowlistResolver {
+    allowed_bases: Vec<PathBuf>,
+    allow_data_uris: bool,
+}
+
+impl AllowlistResolver {
+    /// Create a resolver that only permits reads under `allowed_bases`.
+    /// `data:` URIs are allowed by default; see [`Self::deny_data_uris`].
+    pub fn new(allowed_bases: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self { allowed_bases: allowed_bases.into_iter().collect(), allow_data_uris: true }
+    }
+
+    /// Reject `data:` URIs, restricting resolution to `file:`/bare paths.
+    pub fn deny_data_uris(mut self) -> Self {
+        self.allow_data_uris = false;
+        self
+    }
+
+    fn canonicalize_within_allowlist(&self, candidate: &Path) -> Result<PathBuf, ConversionError> {
+        let canonical =
+            candidate.canonicalize().map_err(|_| ConversionError::ResourceAccessDenied)?;
+
+        let allowed = self.allowed_bases.iter().any(|base| {
+            base.canonicalize().map(|base| canonical.starts_with(base)).unwrap_or(false)
+        });
+
+        if allowed {
+            Ok(canonical)
+        } else {
+            Err(ConversionError::ResourceAccessDenied)
+        }
+    }
+}
+
+impl ResourceResolver for AllowlistResolver {
+    fn resolve(&self, href: &str, base_dir: &Path) -> Result<Vec<u8>, ConversionError> {
+        if let Some(data) = href.strip_prefix("data:") {
+            return if self.allow_data_uris {
+                decode_data_uri(data)
+            } else {
+                Err(ConversionError::ResourceAccessDenied)
+            };
+        }
+
+        if href.contains("://") && !href.starts_with("file://") {
+            // Only `file://`/bare paths and `data:` URIs are supported; any
+            // other `scheme://` href (http, https, ...) is refused outright.
+            // An href without `://` is treated as a path and still goes
+            // through the allowlist check below.
+            return Err(ConversionError::ResourceAccessDenied);
+        }
+
+        let path = href.strip_prefix("file://").unwrap_or(href);
+        let candidate = base_dir.join(path);
+        let resolved = self.canonicalize_within_allowlist(&candidate)?;
+
+        std::fs::read(resolved).map_err(|_| ConversionError::ResourceAccessDenied)
+    }
+}
+
+fn decode_data_uri(data: &str) -> Result<Vec<u8>, ConversionError> {
+    let (_meta, payload) =
+        data.split_once(',').ok_or(ConversionError::ResourceAccessDenied)?;
+
+    base64::Engine::decode(&base64::engine::general_purpose::STANDARD, payload)
+        .map_err(|_| ConversionError::ResourceAccessDenied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixture {
+        root: PathBuf,
+        allowed: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir()
+                .join(format!("svg2pdf-resolver-test-{name}-{}", std::process::id()));
+            let allowed = root.join("allowed");
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(&allowed).unwrap();
+
+            std::fs::write(allowed.join("inside.txt"), b"inside").unwrap();
+            std::fs::write(root.join("secret.txt"), b"secret").unwrap();
+
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(root.join("secret.txt"), allowed.join("escape-link.txt"))
+                .unwrap();
+
+            Self { root, allowed }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn allows_reads_inside_the_allowlist() {
+        let fx = Fixture::new("allow");
+        let resolver = AllowlistResolver::new([fx.allowed.clone()]);
+
+        let bytes = resolver.resolve("inside.txt", &fx.allowed).unwrap();
+        assert_eq!(bytes, b"inside");
+    }
+
+    #[test]
+    fn rejects_dot_dot_traversal() {
+        let fx = Fixture::new("traversal");
+        let resolver = AllowlistResolver::new([fx.allowed.clone()]);
+
+        let err = resolver.resolve("../secret.txt", &fx.allowed).unwrap_err();
+        assert!(matches!(err, ConversionError::ResourceAccessDenied));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escapes() {
+        let fx = Fixture::new("symlink");
+        let resolver = AllowlistResolver::new([fx.allowed.clone()]);
+
+        let err = resolver.resolve("escape-link.txt", &fx.allowed).unwrap_err();
+        assert!(matches!(err, ConversionError::ResourceAccessDenied));
+    }
+
+    #[test]
+    fn rejects_disallowed_schemes() {
+        let fx = Fixture::new("scheme");
+        let resolver = AllowlistResolver::new([fx.allowed.clone()]);
+
+        let err = resolver.resolve("https://example.com/evil.png", &fx.allowed).unwrap_err();
+        assert!(matches!(err, ConversionError::ResourceAccessDenied));
+    }
+
+    #[test]
+    fn decodes_data_uris_by_default() {
+        let fx = Fixture::new("data-uri");
+        let resolver = AllowlistResolver::new([fx.allowed.clone()]);
+
+        // base64 for "hi"
+        let bytes = resolver.resolve("data:text/plain;base64,aGk=", &fx.allowed).unwrap();
+        assert_eq!(bytes, b"hi");
+    }
+
+    #[test]
+    fn deny_data_uris_rejects_them() {
+        let fx = Fixture::new("deny-data-uri");
+        let resolver = AllowlistResolver::new([fx.allowed.clone()]).deny_data_uris();
+
+        let err = resolver.resolve("data:text/plain;base64,aGk=", &fx.allowed).unwrap_err();
+        assert!(matches!(err, ConversionError::ResourceAccessDenied));
+    }
+}
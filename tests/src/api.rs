@@ -93,6 +93,103 @@ fn to_chunk() {
     assert_eq!(res, 0);
 }
 
+/// Count `/Type /Page` objects in a serialized PDF, excluding the single
+/// `/Type /Pages` tree root. There's no PDF parser in this test crate, so
+/// this scans for the literal token pair the way `pdf_writer` emits it.
+fn count_page_objects(pdf: &[u8]) -> usize {
+    let text = String::from_utf8_lossy(pdf);
+    let needle = "/Type /Page";
+    let mut count = 0;
+    let mut search_from = 0;
+
+    while let Some(offset) = text[search_from..].find(needle) {
+        let match_end = search_from + offset + needle.len();
+        if text.as_bytes().get(match_end) != Some(&b's') {
+            count += 1;
+        }
+        search_from = match_end;
+    }
+
+    count
+}
+
+/// Extract every `/MediaBox [x0 y0 x1 y1]` array from a serialized PDF, in
+/// the order they appear.
+fn media_boxes(pdf: &[u8]) -> Vec<[f64; 4]> {
+    let text = String::from_utf8_lossy(pdf);
+    let mut boxes = Vec::new();
+    let mut search_from = 0;
+
+    while let Some(offset) = text[search_from..].find("/MediaBox") {
+        let rest = &text[search_from + offset..];
+        let start = rest.find('[').unwrap();
+        let end = rest.find(']').unwrap();
+        let values: Vec<f64> =
+            rest[start + 1..end].split_whitespace().map(|s| s.parse().unwrap()).collect();
+        boxes.push([values[0], values[1], values[2], values[3]]);
+        search_from += offset + end;
+    }
+
+    boxes
+}
+
+/// Test that `to_pdf_multipage` packs several trees into one document, each
+/// on its own page, and produces a valid standalone PDF.
+#[test]
+fn to_pdf_multipage() {
+    let paths = [
+        "svg/resvg/text/text/simple-case.svg",
+        "svg/custom/integration/wikimedia/coat_of_the_arms_of_edinburgh_city_council.svg",
+    ];
+
+    let options = usvg::Options { fontdb: FONTDB.clone(), ..usvg::Options::default() };
+    let trees: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            let svg = std::fs::read_to_string(path).unwrap();
+            usvg::Tree::from_str(&svg, &options).unwrap()
+        })
+        .collect();
+
+    let page_options = PageOptions::default();
+    let input: Vec<_> = trees.iter().map(|tree| (tree, page_options)).collect();
+
+    let pdf_bytes =
+        svg2pdf::to_pdf_multipage(&input, ConversionOptions::default()).unwrap();
+
+    assert!(pdf_bytes.starts_with(b"%PDF"), "Output should be a valid PDF");
+
+    assert_eq!(
+        count_page_objects(&pdf_bytes),
+        trees.len(),
+        "one page object should be emitted per input tree"
+    );
+
+    let boxes = media_boxes(&pdf_bytes);
+    assert_eq!(boxes.len(), trees.len(), "one media box should be emitted per page");
+
+    for (tree, media_box) in trees.iter().zip(&boxes) {
+        let size = tree.size();
+        let expected_w = (size.width() / page_options.dpi * 72.0) as f64;
+        let expected_h = (size.height() / page_options.dpi * 72.0) as f64;
+
+        assert_eq!(media_box[0], 0.0);
+        assert_eq!(media_box[1], 0.0);
+        assert!(
+            (media_box[2] - expected_w).abs() < 1.0,
+            "media box width should match the tree's own size at the page dpi"
+        );
+        assert!(
+            (media_box[3] - expected_h).abs() < 1.0,
+            "media box height should match the tree's own size at the page dpi"
+        );
+    }
+
+    // The two input trees differ, so each page's media box should too —
+    // otherwise this test would pass even if per-tree sizing were broken.
+    assert_ne!(boxes[0], boxes[1], "distinct trees should produce distinct page sizes");
+}
+
 /// Test that the external image provider is called and the resulting PDF
 /// contains the externally-provided image in the correct position.
 #[test]
@@ -246,3 +343,114 @@ fn no_image_provider() {
     // The chunk must contain data (the encoded image).
     assert!(chunk.len() > 0, "Chunk should be non-empty for SVG with image");
 }
+
+/// Test that `tree_to_xobject`'s dedup cache (via `to_pdf_multipage`, which
+/// routes every page through one shared `Context`) reuses a single XObject
+/// when the exact same tree is drawn on two pages, while two genuinely
+/// different trees each still get their own.
+#[test]
+fn xobject_dedup_across_pages() {
+    let options = usvg::Options { fontdb: FONTDB.clone(), ..usvg::Options::default() };
+
+    let svg_a = std::fs::read_to_string(
+        "svg/custom/integration/wikimedia/coat_of_the_arms_of_edinburgh_city_council.svg",
+    )
+    .unwrap();
+    let tree_a = svg2pdf::usvg::Tree::from_str(&svg_a, &options).unwrap();
+
+    let svg_b = std::fs::read_to_string("svg/resvg/text/text/simple-case.svg").unwrap();
+    let tree_b = svg2pdf::usvg::Tree::from_str(&svg_b, &options).unwrap();
+
+    let page_options = PageOptions::default();
+
+    let same_tree_twice = svg2pdf::to_pdf_multipage(
+        &[(&tree_a, page_options), (&tree_a, page_options)],
+        ConversionOptions::default(),
+    )
+    .unwrap();
+
+    let two_different_trees = svg2pdf::to_pdf_multipage(
+        &[(&tree_a, page_options), (&tree_b, page_options)],
+        ConversionOptions::default(),
+    )
+    .unwrap();
+
+    assert!(
+        same_tree_twice.len() < two_different_trees.len(),
+        "drawing the same tree on two pages should reuse a single cached XObject \
+         instead of emitting it twice, so the document should be smaller than one \
+         with two distinct trees"
+    );
+}
+
+/// Test that the XObject dedup cache keys on each page's own DPI, not just
+/// the tree, so two pages that share a tree but render it at different DPIs
+/// each get an embedded image downsampled for their own page instead of
+/// silently inheriting whichever page happened to populate the cache first.
+#[test]
+fn xobject_dedup_respects_per_page_dpi() {
+    let path = "svg/custom/structure/image/png-rgb-8.svg";
+    let svg = std::fs::read_to_string(path).unwrap();
+    let options = usvg::Options { fontdb: FONTDB.clone(), ..usvg::Options::default() };
+    let tree = svg2pdf::usvg::Tree::from_str(&svg, &options).unwrap();
+
+    let conversion_options =
+        ConversionOptions { max_image_dpi: Some(9.0), ..ConversionOptions::default() };
+
+    let low_dpi = PageOptions { dpi: 48.0 };
+    let high_dpi = PageOptions { dpi: 192.0 };
+
+    let same_dpi_twice = svg2pdf::to_pdf_multipage(
+        &[(&tree, low_dpi), (&tree, low_dpi)],
+        conversion_options,
+    )
+    .unwrap();
+
+    let two_different_dpis = svg2pdf::to_pdf_multipage(
+        &[(&tree, low_dpi), (&tree, high_dpi)],
+        conversion_options,
+    )
+    .unwrap();
+
+    assert!(
+        two_different_dpis.len() > same_dpi_twice.len(),
+        "pages at different DPIs must not share one cached, pre-downsampled \
+         image XObject, since that would fix the second page's image \
+         resolution to whatever the first page needed"
+    );
+}
+
+/// Test that setting `max_image_dpi` actually shrinks the embedded image
+/// relative to the native-resolution default, and that JPEG recompression
+/// shrinks it further still.
+#[test]
+fn max_image_dpi_downsamples() {
+    let path = "svg/custom/structure/image/png-rgb-8.svg";
+    let svg = std::fs::read_to_string(path).unwrap();
+    let options = usvg::Options { fontdb: FONTDB.clone(), ..usvg::Options::default() };
+    let tree = svg2pdf::usvg::Tree::from_str(&svg, &options).unwrap();
+
+    let (native_chunk, _) =
+        svg2pdf::to_chunk(&tree, ConversionOptions::default()).unwrap();
+
+    let downsampled_opts =
+        ConversionOptions { max_image_dpi: Some(9.0), ..ConversionOptions::default() };
+    let (downsampled_chunk, _) = svg2pdf::to_chunk(&tree, downsampled_opts).unwrap();
+
+    assert!(
+        downsampled_chunk.len() < native_chunk.len(),
+        "Downsampling to a low max_image_dpi should shrink the chunk"
+    );
+
+    let recompressed_opts = ConversionOptions {
+        max_image_dpi: Some(9.0),
+        image_recompress: Some(svg2pdf::ImageEncoding::Jpeg { quality: 60 }),
+        ..ConversionOptions::default()
+    };
+    let (recompressed_chunk, _) = svg2pdf::to_chunk(&tree, recompressed_opts).unwrap();
+
+    assert!(
+        recompressed_chunk.len() < downsampled_chunk.len(),
+        "JPEG recompression should shrink the chunk further than downsampling alone"
+    );
+}